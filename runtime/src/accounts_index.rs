@@ -0,0 +1,116 @@
+use solana_sdk::clock::Slot;
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, RwLock};
+
+pub type SlotList<T> = Vec<(Slot, T)>;
+pub type RefCount = u64;
+// age is stored as a u8 and wraps. it is only ever compared for "how far apart",
+// never for absolute ordering, so wrapping is fine.
+pub type Age = u8;
+
+pub trait IsCached: Clone + Debug + Sync + Send {
+    fn is_cached(&self) -> bool;
+}
+
+// one entry in the index for a given pubkey.
+// owns the slot list (the history of (slot, account_info) for this pubkey)
+// and the bookkeeping the in-mem bin needs to decide when it is safe to
+// age this entry out to disk.
+#[derive(Debug)]
+pub struct AccountMapEntryInner<T> {
+    ref_count: AtomicU64,
+    pub slot_list: RwLock<SlotList<T>>,
+    // last Age this entry was touched by a get/entry/upsert. Used by the
+    // background flusher to decide whether this entry is cold enough to evict.
+    age: AtomicU8,
+}
+
+impl<T: IsCached> AccountMapEntryInner<T> {
+    pub fn new(slot_list: SlotList<T>, ref_count: RefCount) -> Self {
+        Self {
+            ref_count: AtomicU64::new(ref_count),
+            slot_list: RwLock::new(slot_list),
+            age: AtomicU8::new(0),
+        }
+    }
+
+    pub fn ref_count(&self) -> RefCount {
+        self.ref_count.load(Ordering::Acquire)
+    }
+
+    pub fn add_un_ref(&self, add: bool) {
+        if add {
+            self.ref_count.fetch_add(1, Ordering::Release);
+        } else {
+            self.ref_count.fetch_sub(1, Ordering::Release);
+        }
+    }
+
+    pub fn set_ref_count(&self, ref_count: RefCount) {
+        self.ref_count.store(ref_count, Ordering::Release);
+    }
+
+    pub fn age(&self) -> Age {
+        self.age.load(Ordering::Acquire)
+    }
+
+    pub fn set_age(&self, age: Age) {
+        self.age.store(age, Ordering::Release);
+    }
+}
+
+pub type AccountMapEntry<T> = Arc<AccountMapEntryInner<T>>;
+
+// held while updating an entry that was already occupied so the caller can
+// finish applying the new slot list entry without re-looking-up the bin.
+#[derive(Debug)]
+pub struct WriteAccountMapEntry<T: IsCached> {
+    pub entry: AccountMapEntry<T>,
+}
+
+impl<T: IsCached> WriteAccountMapEntry<T> {
+    pub fn from_account_map_entry(entry: AccountMapEntry<T>) -> Self {
+        Self { entry }
+    }
+}
+
+// The common case for `upsert`/`insert_new_entry_if_missing_with_lock` is that
+// the key already exists, in which case only the raw (slot, account_info) is
+// needed to update the existing slot list -- building the `Arc<AccountMapEntryInner>`
+// (and its internal `RwLock<SlotList>`) would be wasted work that gets torn
+// down immediately. `Raw` defers that allocation until `into_account_map_entry`
+// is actually called on the vacant-entry path.
+#[derive(Debug)]
+pub enum PreAllocatedAccountMapEntry<T: IsCached> {
+    Entry(AccountMapEntry<T>),
+    Raw { slot: Slot, account_info: T },
+}
+
+impl<T: IsCached> PreAllocatedAccountMapEntry<T> {
+    pub fn new(slot: Slot, account_info: T) -> Self {
+        Self::Raw { slot, account_info }
+    }
+
+    // materialize the heap-allocated `Arc` form. Only actually allocates when
+    // called on the `Raw` variant.
+    pub fn into_account_map_entry(self) -> AccountMapEntry<T> {
+        match self {
+            Self::Entry(entry) => entry,
+            Self::Raw { slot, account_info } => {
+                let ref_count = RefCount::from(!account_info.is_cached());
+                Arc::new(AccountMapEntryInner::new(vec![(slot, account_info)], ref_count))
+            }
+        }
+    }
+
+    // pull the (slot, account_info) back out without ever allocating, used on
+    // the path where the key already exists and we only need to fold this
+    // update into the existing entry's slot list.
+    pub fn take_as_slot_and_account_info(self) -> (Slot, T) {
+        match self {
+            Self::Raw { slot, account_info } => (slot, account_info),
+            Self::Entry(entry) => entry.slot_list.write().unwrap().remove(0),
+        }
+    }
+}
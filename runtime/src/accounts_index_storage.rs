@@ -1,31 +1,52 @@
+use crate::accounts_index::IsCached;
 use crate::bucket_map_holder::BucketMapHolder;
+use crate::in_mem_accounts_index::InMemAccountsIndex;
 use crate::waitable_condvar::WaitableCondvar;
 use std::{
+    fmt::Debug,
+    path::Path,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
     thread::{Builder, JoinHandle},
+    time::Duration,
 };
 
-// eventually hold the bucket map
+// how often the background thread wakes up to look for cold entries to age out
+const AGE_INTERVAL_MS: u64 = 400;
+
+// Owns the in-mem index bins and the on-disk bucket map storage they spill
+// cold entries to.
 // Also manages the lifetime of the background processing threads.
 //  When this instance is dropped, it will drop the bucket map and cleanup
 //  and it will stop all the background threads and join them.
-
-#[derive(Debug, Default)]
-pub struct AccountsIndexStorage {
+//
+// `InMemAccountsIndex` is internally synchronized (see `map_internal`), so
+// bins are shared as plain `Arc`s -- no outer lock needed to reach them from
+// both the background thread and normal index callers concurrently.
+#[derive(Debug)]
+pub struct AccountsIndexStorage<T: IsCached + serde::Serialize + serde::de::DeserializeOwned> {
     // for managing the bg threads
     exit: Arc<AtomicBool>,
     wait: Arc<WaitableCondvar>,
     handle: Option<JoinHandle<()>>,
 
-    // eventually the backing storage
-    storage: Arc<BucketMapHolder>,
+    // shared age/stats state plus the on-disk bucket map backing storage
+    storage: Arc<BucketMapHolder<T>>,
+    in_mem: Vec<Arc<InMemAccountsIndex<T>>>,
 }
 
-impl Drop for AccountsIndexStorage {
+impl<T: IsCached + serde::Serialize + serde::de::DeserializeOwned> Drop for AccountsIndexStorage<T> {
     fn drop(&mut self) {
+        // Make disk authoritative before a future restart's `rebuild_from_disk`
+        // reads it back: `flush` on its own only ever persists entries it
+        // evicts, so anything still resident (never went cold, or was
+        // modified since its last eviction) would otherwise be missing or
+        // stale on disk.
+        for bin in &self.in_mem {
+            bin.flush_all_resident_to_disk();
+        }
         self.exit.store(true, Ordering::Relaxed);
         self.wait.notify_all();
         if let Some(x) = self.handle.take() {
@@ -34,19 +55,36 @@ impl Drop for AccountsIndexStorage {
     }
 }
 
-impl AccountsIndexStorage {
-    pub fn new() -> AccountsIndexStorage {
-        let storage = Arc::new(BucketMapHolder::new());
-        let storage_ = storage.clone();
+impl<T> AccountsIndexStorage<T>
+where
+    T: IsCached + serde::Serialize + serde::de::DeserializeOwned + 'static,
+{
+    // `disk_path` is where each bin's on-disk bucket lives; an existing
+    // directory from a prior run is picked back up automatically, and each
+    // bin immediately reloads whatever it had already spilled there (see
+    // `InMemAccountsIndex::rebuild_from_disk`), so a validator restart
+    // doesn't start every bin looking empty.
+    pub fn new(bins: usize, disk_path: &Path) -> AccountsIndexStorage<T> {
+        let storage = Arc::new(BucketMapHolder::new(bins, disk_path));
+        let in_mem: Vec<_> = (0..bins)
+            .map(|bin| {
+                let bin = Arc::new(InMemAccountsIndex::new(&storage, bin));
+                bin.rebuild_from_disk();
+                bin
+            })
+            .collect();
+
         let exit = Arc::new(AtomicBool::default());
         let exit_ = exit.clone();
         let wait = Arc::new(WaitableCondvar::default());
         let wait_ = wait.clone();
+        let storage_ = storage.clone();
+        let in_mem_ = in_mem.clone();
         let handle = Some(
             Builder::new()
                 .name("solana-index-flusher".to_string())
                 .spawn(move || {
-                    storage_.background(exit_, wait_);
+                    Self::background(exit_, wait_, storage_, in_mem_);
                 })
                 .unwrap(),
         );
@@ -56,10 +94,35 @@ impl AccountsIndexStorage {
             wait,
             handle,
             storage,
+            in_mem,
         }
     }
 
-    pub fn storage(&self) -> &Arc<BucketMapHolder> {
+    // periodically bumps the shared Age and asks every dirty bin to flush
+    // whatever has gone cold since its last pass
+    fn background(
+        exit: Arc<AtomicBool>,
+        wait: Arc<WaitableCondvar>,
+        storage: Arc<BucketMapHolder<T>>,
+        in_mem: Vec<Arc<InMemAccountsIndex<T>>>,
+    ) {
+        while !exit.load(Ordering::Relaxed) {
+            wait.wait_timeout(Duration::from_millis(AGE_INTERVAL_MS));
+            storage.bump_age();
+            let current_age = storage.current_age();
+            for bin in in_mem.iter() {
+                if storage.is_bin_dirty(bin.bin()) {
+                    bin.flush(current_age);
+                }
+            }
+        }
+    }
+
+    pub fn storage(&self) -> &Arc<BucketMapHolder<T>> {
         &self.storage
     }
+
+    pub fn in_mem(&self) -> &[Arc<InMemAccountsIndex<T>>] {
+        &self.in_mem
+    }
 }
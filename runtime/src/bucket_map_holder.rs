@@ -0,0 +1,105 @@
+use crate::accounts_index::{Age, IsCached, RefCount, SlotList};
+use crate::bucket_map_holder_stats::BucketMapHolderStats;
+use crate::disk_bucket::DiskBucket;
+use solana_sdk::pubkey::Pubkey;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+// how many age ticks an entry can go untouched before the flusher considers
+// it cold enough to write out and evict from the in-mem map
+pub const AGE_TO_FLUSH: Age = 4;
+
+// per-bin bookkeeping for the background flusher
+#[derive(Debug, Default)]
+pub struct BinLifetime {
+    // set whenever a bin is mutated, cleared once the flusher has passed over it
+    pub bin_dirty: AtomicBool,
+    pub last_age_flushed: AtomicU8,
+}
+
+// shared state for every bin of the accounts index: the current Age, per-bin
+// flush bookkeeping, and the on-disk bucket map bins spill cold entries to.
+#[derive(Debug)]
+pub struct BucketMapHolder<T: IsCached> {
+    pub stats: BucketMapHolderStats,
+    age: AtomicU8,
+    pub bins: Vec<BinLifetime>,
+    disk: Vec<DiskBucket<T>>,
+}
+
+impl<T: IsCached> BucketMapHolder<T> {
+    pub fn bins(&self) -> usize {
+        self.bins.len()
+    }
+
+    pub fn current_age(&self) -> Age {
+        self.age.load(Ordering::Acquire)
+    }
+
+    // called once per background pass
+    pub fn bump_age(&self) {
+        self.age.fetch_add(1, Ordering::Release);
+    }
+
+    pub fn set_bin_dirty(&self, bin: usize) {
+        self.bins[bin].bin_dirty.store(true, Ordering::Release);
+    }
+
+    pub fn is_bin_dirty(&self, bin: usize) -> bool {
+        self.bins[bin].bin_dirty.load(Ordering::Acquire)
+    }
+}
+
+impl<T> BucketMapHolder<T>
+where
+    T: IsCached + serde::Serialize + serde::de::DeserializeOwned,
+{
+    pub fn new(bins: usize, disk_path: &Path) -> Self {
+        Self {
+            stats: BucketMapHolderStats::default(),
+            age: AtomicU8::new(0),
+            bins: (0..bins).map(|_| BinLifetime::default()).collect(),
+            disk: (0..bins).map(|bin| DiskBucket::new(disk_path, bin)).collect(),
+        }
+    }
+
+    pub fn write_to_disk(&self, bin: usize, pubkey: &Pubkey, slot_list: SlotList<T>, ref_count: RefCount) {
+        self.disk[bin].write(pubkey, &slot_list, ref_count);
+    }
+
+    // Same as `write_to_disk`, but defers the blob rewrite. Used by the
+    // flusher, which evicts many entries from a bin in one pass -- see
+    // `persist_to_disk`.
+    pub fn write_to_disk_buffered(&self, bin: usize, pubkey: &Pubkey, slot_list: SlotList<T>, ref_count: RefCount) {
+        self.disk[bin].insert(pubkey, &slot_list, ref_count);
+    }
+
+    // Rewrites `bin`'s on-disk blob once with whatever `write_to_disk_buffered`
+    // calls have accumulated since the last persist.
+    pub fn persist_to_disk(&self, bin: usize) {
+        self.disk[bin].persist();
+    }
+
+    pub fn read_from_disk(&self, bin: usize, pubkey: &Pubkey) -> Option<(SlotList<T>, RefCount)> {
+        self.disk[bin].read(pubkey)
+    }
+
+    // Like `read_from_disk`, but for callers (e.g. reclaim accounting) that
+    // only need the refcount and shouldn't pay to clone a slot list they're
+    // just going to throw away.
+    pub fn disk_ref_count(&self, bin: usize, pubkey: &Pubkey) -> Option<RefCount> {
+        self.disk[bin].read_ref_count(pubkey)
+    }
+
+    pub fn delete_from_disk(&self, bin: usize, pubkey: &Pubkey) {
+        self.disk[bin].delete(pubkey);
+    }
+
+    // every pubkey already persisted in `bin`'s on-disk bucket. Used to find
+    // which keys need reloading into the in-mem bin (rebuilding its resident
+    // set after a restart, or pulling a held range back in) without the
+    // caller needing to already know what those keys are.
+    pub fn disk_keys(&self, bin: usize) -> Vec<Pubkey> {
+        self.disk[bin].keys()
+    }
+}
@@ -0,0 +1,13 @@
+use std::sync::atomic::AtomicU64;
+
+// stats shared by every bin of the accounts index, tracked in `BucketMapHolder`
+// so `InMemAccountsIndex` can update them without needing its own copy.
+#[derive(Debug, Default)]
+pub struct BucketMapHolderStats {
+    pub gets_from_mem: AtomicU64,
+    pub get_mem_us: AtomicU64,
+    pub gets_missing: AtomicU64,
+    pub get_missing_us: AtomicU64,
+    pub items: AtomicU64,
+    pub keys: AtomicU64,
+}
@@ -0,0 +1,97 @@
+use crate::accounts_index::{IsCached, RefCount, SlotList};
+use solana_sdk::pubkey::Pubkey;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+// File-backed store for a single accounts-index bin. The whole bin is
+// serialized as one blob and mirrored in memory so `read`/`write`/`delete`
+// are simple map operations; the in-memory mirror is re-persisted to disk on
+// every mutation (see `insert`/`persist` for the batched form a caller
+// writing many entries in one pass should use instead). Loaded once at
+// startup (see `new`) so this bucket's own entries survive a restart without
+// replaying the whole transaction history into RAM.
+#[derive(Debug)]
+pub struct DiskBucket<T> {
+    path: PathBuf,
+    entries: RwLock<HashMap<Pubkey, (SlotList<T>, RefCount)>>,
+}
+
+impl<T> DiskBucket<T>
+where
+    T: IsCached + serde::Serialize + serde::de::DeserializeOwned,
+{
+    pub fn new(dir: &Path, bin: usize) -> Self {
+        fs::create_dir_all(dir).expect("accounts index disk dir");
+        let path = dir.join(format!("bucket_{}.bin", bin));
+        let entries = fs::read(&path)
+            .ok()
+            .and_then(|bytes| bincode::deserialize(&bytes).ok())
+            .unwrap_or_default();
+        Self {
+            path,
+            entries: RwLock::new(entries),
+        }
+    }
+
+    pub fn read(&self, pubkey: &Pubkey) -> Option<(SlotList<T>, RefCount)>
+    where
+        T: Clone,
+    {
+        self.entries.read().unwrap().get(pubkey).cloned()
+    }
+
+    // Like `read`, but for callers that only need the refcount and shouldn't
+    // pay for cloning (and the caller throwing away) the full slot list.
+    pub fn read_ref_count(&self, pubkey: &Pubkey) -> Option<RefCount> {
+        self.entries.read().unwrap().get(pubkey).map(|(_, ref_count)| *ref_count)
+    }
+
+    pub fn write(&self, pubkey: &Pubkey, slot_list: &SlotList<T>, ref_count: RefCount)
+    where
+        T: Clone,
+    {
+        self.insert(pubkey, slot_list, ref_count);
+        self.persist();
+    }
+
+    // Same update as `write`, but skips the per-call persist. A caller that is
+    // about to write many entries in one pass (e.g. the background flusher
+    // evicting a whole bin's worth of cold entries) should use this and call
+    // `persist` once at the end, instead of re-serializing and rewriting the
+    // whole bin file after every single entry.
+    pub fn insert(&self, pubkey: &Pubkey, slot_list: &SlotList<T>, ref_count: RefCount)
+    where
+        T: Clone,
+    {
+        self.entries
+            .write()
+            .unwrap()
+            .insert(*pubkey, (slot_list.clone(), ref_count));
+    }
+
+    pub fn delete(&self, pubkey: &Pubkey) {
+        let removed = self.entries.write().unwrap().remove(pubkey).is_some();
+        if removed {
+            self.persist();
+        }
+    }
+
+    // every pubkey currently persisted for this bin. Used to find which keys
+    // need reloading into a bin's in-mem map (e.g. rebuilding its resident
+    // set after a restart, or pulling a held range back in) without the
+    // caller needing to already know what those keys are.
+    pub fn keys(&self) -> Vec<Pubkey> {
+        self.entries.read().unwrap().keys().cloned().collect()
+    }
+
+    pub fn persist(&self) {
+        let entries = self.entries.read().unwrap();
+        if let Ok(bytes) = bincode::serialize(&*entries) {
+            // best-effort: a failed persist just means this entry is re-read
+            // from its in-mem copy and re-tried on the next mutation
+            let _ = fs::write(&self.path, bytes);
+        }
+    }
+}
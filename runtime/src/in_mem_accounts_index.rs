@@ -1,135 +1,141 @@
 use crate::accounts_index::{
-    AccountMapEntry, AccountMapEntryInner, IsCached, SlotList, WriteAccountMapEntry,
+    AccountMapEntry, AccountMapEntryInner, Age, IsCached, PreAllocatedAccountMapEntry, RefCount,
+    SlotList, WriteAccountMapEntry,
 };
-use crate::accounts_index_storage::AccountsIndexStorage;
 use crate::bucket_map_holder::BucketMapHolder;
 use crate::bucket_map_holder_stats::BucketMapHolderStats;
 use solana_measure::measure::Measure;
 use solana_sdk::{clock::Slot, pubkey::Pubkey};
-use std::collections::{
-    hash_map::{Entry, Keys},
-    HashMap,
-};
+use std::collections::{hash_map::Entry, HashMap};
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 
 use std::fmt::Debug;
-use std::ops::RangeBounds;
+use std::ops::{RangeBounds, RangeInclusive};
 type K = Pubkey;
 
 // one instance of this represents one bin of the accounts index.
 #[derive(Debug)]
 pub struct InMemAccountsIndex<T: IsCached> {
-    // backing store
-    map: HashMap<Pubkey, AccountMapEntry<T>>,
-    storage: Arc<BucketMapHolder>,
+    // backing store. `&self` suffices for every operation: reads take a read
+    // lock, inserts/removes take a write lock, and an upsert into an existing
+    // key only ever needs a read lock here because the slot list inside
+    // `AccountMapEntry` has its own internal lock.
+    map_internal: RwLock<HashMap<Pubkey, AccountMapEntry<T>>>,
+    storage: Arc<BucketMapHolder<T>>,
+    bin: usize,
+    // pubkey ranges a scan is currently relying on being fully resident. While
+    // any entry here is `Some`, the background flusher must not evict
+    // anything from this bin at all (we don't know which key in the range it
+    // would touch next).
+    cache_ranges_held: RwLock<Vec<Option<RangeInclusive<Pubkey>>>>,
+    // like `cache_ranges_held`, but for callers that need the whole bin
+    // pinned rather than a specific range. Nests: every `true` call must be
+    // matched by a `false` call.
+    stop_flush: AtomicU64,
 }
 
 impl<T: IsCached> InMemAccountsIndex<T> {
-    pub fn new(storage: &AccountsIndexStorage) -> Self {
+    pub fn new(storage: &Arc<BucketMapHolder<T>>, bin: usize) -> Self {
         Self {
-            map: HashMap::new(),
-            storage: storage.storage().clone(),
+            map_internal: RwLock::new(HashMap::new()),
+            storage: storage.clone(),
+            bin,
+            cache_ranges_held: RwLock::new(Vec::default()),
+            stop_flush: AtomicU64::new(0),
         }
     }
 
-    pub fn new_bucket_map_holder() -> Arc<BucketMapHolder> {
-        Arc::new(BucketMapHolder::new())
+    pub fn bin(&self) -> usize {
+        self.bin
     }
 
-    pub fn entry(&mut self, pubkey: Pubkey) -> Entry<K, AccountMapEntry<T>> {
-        let m = Measure::start("entry");
-        let result = self.map.entry(pubkey);
-        let stats = &self.storage.stats;
-        let (count, time) = if matches!(result, Entry::Occupied(_)) {
-            (&stats.gets_from_mem, &stats.get_mem_us)
+    // Pin (or release) this entire bin in memory, regardless of range.
+    pub fn start_stop_flush(&self, stop: bool) {
+        if stop {
+            self.stop_flush.fetch_add(1, Ordering::Release);
         } else {
-            (&stats.gets_missing, &stats.get_missing_us)
-        };
-        Self::update_time_stat(time, m);
-        Self::update_stat(count, 1);
-        result
-    }
-
-    pub fn items<R>(&self, range: &Option<&R>) -> Vec<(K, AccountMapEntry<T>)>
-    where
-        R: RangeBounds<Pubkey> + std::fmt::Debug,
-    {
-        Self::update_stat(&self.stats().items, 1);
-        let mut result = Vec::with_capacity(self.map.len());
-        self.map.iter().for_each(|(k, v)| {
-            if range.map(|range| range.contains(k)).unwrap_or(true) {
-                result.push((*k, v.clone()));
-            }
-        });
-        result
-    }
-
-    pub fn keys(&self) -> Keys<K, AccountMapEntry<T>> {
-        Self::update_stat(&self.stats().keys, 1);
-        self.map.keys()
+            // saturate instead of wrapping: an unbalanced call (one more
+            // `false` than `true`) would otherwise underflow to u64::MAX and
+            // permanently wedge this bin's flusher off rather than just being
+            // a no-op.
+            let _ = self
+                .stop_flush
+                .fetch_update(Ordering::Release, Ordering::Acquire, |count| {
+                    Some(count.saturating_sub(1))
+                });
+        }
     }
 
-    pub fn get(&self, key: &K) -> Option<AccountMapEntry<T>> {
-        let m = Measure::start("get");
-        let result = self.map.get(key).cloned();
-        let stats = self.stats();
-        let (count, time) = if result.is_some() {
-            (&stats.gets_from_mem, &stats.get_mem_us)
-        } else {
-            (&stats.gets_missing, &stats.get_missing_us)
-        };
-        Self::update_time_stat(time, m);
-        Self::update_stat(count, 1);
-        result
+    // true while a scan is relying on this bin staying fully resident
+    fn is_flush_blocked(&self) -> bool {
+        self.stop_flush.load(Ordering::Acquire) > 0
+            || !self.cache_ranges_held.read().unwrap().is_empty()
     }
 
-    // If the slot list for pubkey exists in the index and is empty, remove the index entry for pubkey and return true.
-    // Return false otherwise.
-    pub fn remove_if_slot_list_empty(&mut self, pubkey: Pubkey) -> bool {
-        if let Entry::Occupied(index_entry) = self.map.entry(pubkey) {
-            if index_entry.get().slot_list.read().unwrap().is_empty() {
-                index_entry.remove();
-                return true;
-            }
-        }
-        false
-    }
     pub fn upsert(
-        &mut self,
+        &self,
         pubkey: &Pubkey,
-        new_value: AccountMapEntry<T>,
+        new_value: PreAllocatedAccountMapEntry<T>,
         reclaims: &mut SlotList<T>,
         previous_slot_entry_was_cached: bool,
     ) {
-        match self.map.entry(*pubkey) {
+        // fast path: the key already exists, so we only need a read lock here
+        // -- the slot list inside the entry has its own internal lock, and we
+        // never materialize `new_value` into an `Arc` at all.
+        {
+            let map = self.map_internal.read().unwrap();
+            if let Some(current) = map.get(pubkey) {
+                let (slot, account_info) = new_value.take_as_slot_and_account_info();
+                Self::lock_and_update_slot_list(
+                    current,
+                    slot,
+                    account_info,
+                    reclaims,
+                    previous_slot_entry_was_cached,
+                );
+                current.set_age(self.storage.current_age());
+                self.storage.set_bin_dirty(self.bin);
+                return;
+            }
+        }
+        // slow path: the key is new (or a writer raced us in since the read
+        // lock above), so we need a write lock to insert it.
+        let mut map = self.map_internal.write().unwrap();
+        match map.entry(*pubkey) {
             Entry::Occupied(mut occupied) => {
                 let current = occupied.get_mut();
+                let (slot, account_info) = new_value.take_as_slot_and_account_info();
                 Self::lock_and_update_slot_list(
                     current,
-                    &new_value,
+                    slot,
+                    account_info,
                     reclaims,
                     previous_slot_entry_was_cached,
                 );
+                current.set_age(self.storage.current_age());
             }
             Entry::Vacant(vacant) => {
-                vacant.insert(new_value);
+                let new_entry = new_value.into_account_map_entry();
+                new_entry.set_age(self.storage.current_age());
+                vacant.insert(new_entry);
             }
         }
+        self.storage.set_bin_dirty(self.bin);
     }
 
     pub fn lock_and_update_slot_list(
         current: &Arc<AccountMapEntryInner<T>>,
-        new_value: &AccountMapEntry<T>,
+        new_slot: Slot,
+        new_account_info: T,
         reclaims: &mut SlotList<T>,
         previous_slot_entry_was_cached: bool,
     ) {
         let mut slot_list = current.slot_list.write().unwrap();
-        let (slot, new_entry) = new_value.slot_list.write().unwrap().remove(0);
         let addref = Self::update_slot_list(
             &mut slot_list,
-            slot,
-            new_entry,
+            new_slot,
+            new_account_info,
             reclaims,
             previous_slot_entry_was_cached,
         );
@@ -175,34 +181,58 @@ impl<T: IsCached> InMemAccountsIndex<T> {
         addref
     }
 
-    pub fn len(&self) -> usize {
-        self.map.len()
-    }
-
-    pub fn is_empty(&self) -> bool {
-        self.len() == 0
-    }
-
     // return None if item was created new
     // if entry for pubkey already existed, return Some(entry). Caller needs to call entry.update.
+    // `ref_count` is only used on the vacant path, where it always replaces
+    // whatever count `new_entry` carried (including `PreAllocatedAccountMapEntry`'s
+    // `is_cached`-derived default for `Raw`). A caller promoting an entry it
+    // already knows the true count for (e.g. one it just read back from the
+    // on-disk bucket) passes that count; every other caller must pass the
+    // count it wants the new entry to start with.
     pub fn insert_new_entry_if_missing_with_lock(
-        &mut self,
+        &self,
         pubkey: Pubkey,
-        new_entry: AccountMapEntry<T>,
+        new_entry: PreAllocatedAccountMapEntry<T>,
+        ref_count: RefCount,
     ) -> Option<(WriteAccountMapEntry<T>, T, Pubkey)> {
-        let account_entry = self.map.entry(pubkey);
-        match account_entry {
-            Entry::Occupied(account_entry) => Some((
-                WriteAccountMapEntry::from_account_map_entry(account_entry.get().clone()),
+        // fast path: only need a read lock if the key is already present, and
+        // we never materialize `new_entry` into an `Arc` at all.
+        {
+            let map = self.map_internal.read().unwrap();
+            if let Some(existing) = map.get(&pubkey) {
+                existing.set_age(self.storage.current_age());
+                self.storage.set_bin_dirty(self.bin);
+                // extract the new account_info from the unused 'new_entry'
+                let (_, account_info) = new_entry.take_as_slot_and_account_info();
+                return Some((
+                    WriteAccountMapEntry::from_account_map_entry(existing.clone()),
+                    account_info,
+                    pubkey,
+                ));
+            }
+        }
+        let mut map = self.map_internal.write().unwrap();
+        let result = match map.entry(pubkey) {
+            Entry::Occupied(account_entry) => {
+                account_entry.get().set_age(self.storage.current_age());
                 // extract the new account_info from the unused 'new_entry'
-                new_entry.slot_list.write().unwrap().remove(0).1,
-                *account_entry.key(),
-            )),
+                let (_, account_info) = new_entry.take_as_slot_and_account_info();
+                Some((
+                    WriteAccountMapEntry::from_account_map_entry(account_entry.get().clone()),
+                    account_info,
+                    *account_entry.key(),
+                ))
+            }
             Entry::Vacant(account_entry) => {
+                let new_entry = new_entry.into_account_map_entry();
+                new_entry.set_ref_count(ref_count);
+                new_entry.set_age(self.storage.current_age());
                 account_entry.insert(new_entry);
                 None
             }
-        }
+        };
+        self.storage.set_bin_dirty(self.bin);
+        result
     }
 
     fn stats(&self) -> &BucketMapHolderStats {
@@ -221,3 +251,282 @@ impl<T: IsCached> InMemAccountsIndex<T> {
         Self::update_stat(stat, value);
     }
 }
+
+// operations that touch the on-disk bucket map need `T` to be serializable
+impl<T> InMemAccountsIndex<T>
+where
+    T: IsCached + serde::Serialize + serde::de::DeserializeOwned,
+{
+    pub fn new_bucket_map_holder(bins: usize, disk_path: &std::path::Path) -> Arc<BucketMapHolder<T>> {
+        Arc::new(BucketMapHolder::new(bins, disk_path))
+    }
+
+    // total live (non-cached) appendvec references across every entry this
+    // bin knows about, in memory or aged out to disk. This sums each entry's
+    // `ref_count()` rather than counting pubkeys, so clean/shrink can answer
+    // "how many references does the index think exist" without re-walking
+    // every slot list. Aging spills entries to disk but must never make them
+    // invisible to this count, so disk-resident keys not currently loaded
+    // into `map_internal` are looked up there too.
+    pub fn get_count_of_entries(&self) -> u64 {
+        let map = self.map_internal.read().unwrap();
+        let in_mem: u64 = map.values().map(|entry| entry.ref_count()).sum();
+        // `disk_ref_count` (not `read_from_disk`) on purpose: only the count is
+        // needed here, so don't clone a slot list just to immediately discard it.
+        let on_disk: u64 = self
+            .storage
+            .disk_keys(self.bin)
+            .into_iter()
+            .filter(|pubkey| !map.contains_key(pubkey))
+            .filter_map(|pubkey| self.storage.disk_ref_count(self.bin, &pubkey))
+            .sum();
+        in_mem + on_disk
+    }
+
+    // Snapshot every entry matching `range` (or the whole bin, for `None`).
+    // Folds in whatever this bin has aged out to disk so the result is
+    // complete even if the caller never held anything -- a full-bin scan
+    // (`range` is `None`) needs this exactly as much as a ranged one, since
+    // it has nothing else guaranteeing the cold half of the bin is included.
+    // Pinning the range first with `hold_range_in_memory` is still required
+    // for *consistency* (so nothing currently in `range` is evicted out from
+    // under a multi-call scan), which this can't provide on its own.
+    pub fn items<R>(&self, range: &Option<&R>) -> Vec<(K, AccountMapEntry<T>)>
+    where
+        R: RangeBounds<Pubkey> + std::fmt::Debug,
+    {
+        Self::update_stat(&self.stats().items, 1);
+        debug_assert!(
+            self.is_flush_blocked(),
+            "scanning {:?} without first calling hold_range_in_memory",
+            range,
+        );
+        let map = self.map_internal.read().unwrap();
+        let mut result = Vec::with_capacity(map.len());
+        map.iter().for_each(|(k, v)| {
+            if range.map(|range| range.contains(k)).unwrap_or(true) {
+                result.push((*k, v.clone()));
+            }
+        });
+        for pubkey in self.storage.disk_keys(self.bin) {
+            if map.contains_key(&pubkey) {
+                continue;
+            }
+            if !range.map(|range| range.contains(&pubkey)).unwrap_or(true) {
+                continue;
+            }
+            if let Some((slot_list, ref_count)) = self.storage.read_from_disk(self.bin, &pubkey) {
+                result.push((pubkey, Arc::new(AccountMapEntryInner::new(slot_list, ref_count))));
+            }
+        }
+        result
+    }
+
+    pub fn keys(&self) -> Vec<K> {
+        Self::update_stat(&self.stats().keys, 1);
+        let map = self.map_internal.read().unwrap();
+        let mut result: Vec<K> = map.keys().cloned().collect();
+        result.extend(
+            self.storage
+                .disk_keys(self.bin)
+                .into_iter()
+                .filter(|pubkey| !map.contains_key(pubkey)),
+        );
+        result
+    }
+
+    // Distinct pubkeys this bin knows about, in memory or aged out to disk.
+    pub fn len(&self) -> usize {
+        let map = self.map_internal.read().unwrap();
+        let on_disk_only = self
+            .storage
+            .disk_keys(self.bin)
+            .into_iter()
+            .filter(|pubkey| !map.contains_key(pubkey))
+            .count();
+        map.len() + on_disk_only
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Pin (or release) `range` in memory for the duration of a scan. While any
+    // range is held, the background flusher skips this bin entirely so every
+    // key the scan will look up via `items` stays resident. Starting a hold
+    // also reloads any key already in `range` that the flusher had evicted
+    // before this call, so the range is actually fully resident by the time
+    // this returns, not just protected from *future* eviction.
+    pub fn hold_range_in_memory(&self, range: &RangeInclusive<Pubkey>, start_holding: bool) {
+        let mut ranges = self.cache_ranges_held.write().unwrap();
+        if start_holding {
+            ranges.push(Some(range.clone()));
+            drop(ranges);
+            self.load_range_from_disk(range);
+        } else if let Some(index) = ranges.iter().position(|r| r.as_ref() == Some(range)) {
+            ranges.remove(index);
+        }
+    }
+
+    // Pull every on-disk key in `range` that isn't already resident back into
+    // `map_internal`, mirroring what `get` does for a single key.
+    fn load_range_from_disk(&self, range: &RangeInclusive<Pubkey>) {
+        for pubkey in self.storage.disk_keys(self.bin) {
+            if !range.contains(&pubkey) {
+                continue;
+            }
+            let mut map = self.map_internal.write().unwrap();
+            if let Entry::Vacant(vacant) = map.entry(pubkey) {
+                if let Some((slot_list, ref_count)) = self.storage.read_from_disk(self.bin, &pubkey) {
+                    let entry = Arc::new(AccountMapEntryInner::new(slot_list, ref_count));
+                    entry.set_age(self.storage.current_age());
+                    vacant.insert(entry);
+                }
+            }
+        }
+    }
+
+    // Called once right after construction so a bin whose on-disk bucket
+    // already has entries from a prior run (i.e. a validator restart, not a
+    // cold start) doesn't come up looking empty to `keys`/`items`/`len` until
+    // each key happens to be individually `get`-promoted.
+    pub fn rebuild_from_disk(&self) {
+        for pubkey in self.storage.disk_keys(self.bin) {
+            if let Some((slot_list, ref_count)) = self.storage.read_from_disk(self.bin, &pubkey) {
+                let entry = Arc::new(AccountMapEntryInner::new(slot_list, ref_count));
+                self.map_internal
+                    .write()
+                    .unwrap()
+                    .entry(pubkey)
+                    .or_insert(entry);
+            }
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<AccountMapEntry<T>> {
+        let m = Measure::start("get");
+        if let Some(entry) = self.map_internal.read().unwrap().get(key).cloned() {
+            Self::update_time_stat(&self.stats().get_mem_us, m);
+            Self::update_stat(&self.stats().gets_from_mem, 1);
+            entry.set_age(self.storage.current_age());
+            return Some(entry);
+        }
+        // RAM miss: fall through to the on-disk bucket and promote the entry
+        // back into `map_internal` on a hit, so the next lookup is fast again.
+        let promoted = self
+            .storage
+            .read_from_disk(self.bin, key)
+            .map(|(slot_list, ref_count)| {
+                let entry: AccountMapEntry<T> = Arc::new(AccountMapEntryInner::new(slot_list, ref_count));
+                entry.set_age(self.storage.current_age());
+                self.map_internal.write().unwrap().insert(*key, entry.clone());
+                entry
+            });
+        Self::update_time_stat(&self.stats().get_missing_us, m);
+        Self::update_stat(&self.stats().gets_missing, 1);
+        promoted
+    }
+
+    // If the slot list for pubkey exists in the index and is empty, remove the index entry for pubkey and return true.
+    // Return false otherwise.
+    //
+    // Also deletes any on-disk copy of `pubkey` for this bin. Without this, a
+    // key that was previously flushed to disk, reloaded via `get`, emptied,
+    // and removed here would still have its stale slot list sitting in the
+    // on-disk bucket -- the next `get` for that key would silently resurrect it.
+    pub fn remove_if_slot_list_empty(&self, pubkey: Pubkey) -> bool {
+        let mut map = self.map_internal.write().unwrap();
+        if let Entry::Occupied(index_entry) = map.entry(pubkey) {
+            if index_entry.get().slot_list.read().unwrap().is_empty() {
+                index_entry.remove();
+                drop(map);
+                self.storage.delete_from_disk(self.bin, &pubkey);
+                self.storage.set_bin_dirty(self.bin);
+                return true;
+            }
+        }
+        false
+    }
+
+    // Called by the background thread when this bin is marked dirty. Writes
+    // out any entry that hasn't been touched in `AGE_TO_FLUSH` ages (and that
+    // nothing else, e.g. a held range scan, is relying on) to the on-disk
+    // bucket and drops it from `map_internal`, bounding how much of the index
+    // stays resident in RAM.
+    pub fn flush(&self, current_age: Age) {
+        if self.is_flush_blocked() {
+            // leave bin_dirty set so we retry as soon as the hold is released
+            return;
+        }
+        let bin = self.bin;
+        let storage = &self.storage;
+        let mut map = self.map_internal.write().unwrap();
+        // The check above is racy: a `hold_range_in_memory`/`start_stop_flush`
+        // call could have landed in the gap between it and taking this write
+        // lock. Re-check now that we hold the lock so we never evict
+        // something a hold that just registered is relying on staying
+        // resident -- leave bin_dirty set so this bin is retried once the
+        // hold is released.
+        if self.is_flush_blocked() {
+            return;
+        }
+        // Clear the dirty mark before scanning, not after: a mutation that
+        // lands anywhere between here and the end of this function calls
+        // `set_bin_dirty` to make sure it gets picked up by a future flush.
+        // Clearing it afterwards instead would erase that mark along with the
+        // one this pass is handling, so a write landing mid-flush could go
+        // un-flushed indefinitely until some unrelated later write re-dirtied
+        // the bin.
+        storage.bins[bin].bin_dirty.store(false, Ordering::Release);
+        let mut evicted_any = false;
+        map.retain(|pubkey, entry| {
+            let age_delta = current_age.wrapping_sub(entry.age());
+            if age_delta < crate::bucket_map_holder::AGE_TO_FLUSH || Arc::strong_count(entry) > 1 {
+                // still hot, or something else holds a reference to it (e.g. a scan)
+                return true;
+            }
+            // buffered: this pass may evict many entries, and re-serializing
+            // and rewriting the whole bin file after each one would turn one
+            // flush pass into O(entries evicted) full-bin rewrites. Persist
+            // once, after the loop, instead.
+            storage.write_to_disk_buffered(
+                bin,
+                pubkey,
+                entry.slot_list.read().unwrap().clone(),
+                entry.ref_count(),
+            );
+            evicted_any = true;
+            false
+        });
+        drop(map);
+        if evicted_any {
+            storage.persist_to_disk(bin);
+        }
+        storage.bins[bin]
+            .last_age_flushed
+            .store(current_age, Ordering::Release);
+    }
+
+    // Persists every entry still resident in this bin, not just the ones
+    // `flush` would age out and evict. Called on shutdown (see
+    // `AccountsIndexStorage::drop`): without it, an entry that never went
+    // cold -- or was modified after its last eviction -- would be missing or
+    // stale on disk, and `rebuild_from_disk` would silently come up short on
+    // the next restart.
+    pub fn flush_all_resident_to_disk(&self) {
+        let map = self.map_internal.read().unwrap();
+        if map.is_empty() {
+            return;
+        }
+        for (pubkey, entry) in map.iter() {
+            self.storage.write_to_disk_buffered(
+                self.bin,
+                pubkey,
+                entry.slot_list.read().unwrap().clone(),
+                entry.ref_count(),
+            );
+        }
+        drop(map);
+        self.storage.persist_to_disk(self.bin);
+    }
+}
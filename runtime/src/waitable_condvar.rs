@@ -0,0 +1,22 @@
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+// encapsulate joinable thread semantics
+#[derive(Debug, Default)]
+pub struct WaitableCondvar {
+    pub mutex: Mutex<bool>,
+    pub event: Condvar,
+}
+
+impl WaitableCondvar {
+    pub fn notify_all(&self) {
+        let _lock = self.mutex.lock().unwrap();
+        self.event.notify_all();
+    }
+
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let lock = self.mutex.lock().unwrap();
+        let (_lock, waited_timed_out) = self.event.wait_timeout(lock, timeout).unwrap();
+        waited_timed_out.timed_out()
+    }
+}